@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::error::Error;
 use std::fs::{self, File};
 use std::io::{self, Write};
@@ -18,6 +19,24 @@ use super::metadata::search_for_section;
 // Re-exporting for rustc_codegen_llvm::back::archive
 pub use crate::errors::{ArchiveBuildFailure, ExtractBundledLibsError, UnknownArchiveKind};
 
+/// Magic number every zstd frame starts with. Used to detect a compressed `.bundled_lib`
+/// section payload without having to introduce a header byte, so uncompressed payloads (the
+/// only format older rustc binaries ever produced) are stored exactly as before.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compresses a native library's bytes for storage in a `.bundled_lib` section. With
+/// `compress: false` (the only behavior before this function existed) `data`
+/// is returned unchanged, so the section payload round-trips identically to before. Intended
+/// for use by whichever code writes `.bundled_lib` sections (see `search_for_section`'s
+/// counterpart on the writing side); `extract_bundled_libs` already handles the compressed
+/// case regardless of which call sites opt in.
+pub fn compress_bundled_lib_data(data: &[u8], compress: bool) -> io::Result<Vec<u8>> {
+    if !compress {
+        return Ok(data.to_vec());
+    }
+    zstd::stream::encode_all(data, 0)
+}
+
 pub trait ArchiveBuilderBuilder {
     fn new_archive_builder<'a>(&self, sess: &'a Session) -> Box<dyn ArchiveBuilder + 'a>;
 
@@ -65,6 +84,13 @@ pub trait ArchiveBuilderBuilder {
             let data = search_for_section(rlib, data, ".bundled_lib").map_err(|e| {
                 ExtractBundledLibsError::ExtractSection { rlib, error: Box::<dyn Error>::from(e) }
             })?;
+            let data = if data.starts_with(&ZSTD_MAGIC) {
+                Cow::Owned(zstd::stream::decode_all(data).map_err(|e| {
+                    ExtractBundledLibsError::Decompress { rlib, error: Box::new(e) }
+                })?)
+            } else {
+                Cow::Borrowed(data)
+            };
             std::fs::write(&outdir.join(&name), data)
                 .map_err(|e| ExtractBundledLibsError::WriteFile { rlib, error: Box::new(e) })?;
         }
@@ -81,6 +107,15 @@ pub trait ArchiveBuilder {
         skip: Box<dyn FnMut(&str) -> bool + 'static>,
     ) -> io::Result<()>;
 
+    /// Chooses whether members preserve their real metadata or are normalized to zero.
+    /// Default: no-op, for builders with no notion of per-member metadata to preserve.
+    fn set_metadata_mode(&mut self, _mode: ArchiveMetadataMode) {}
+
+    /// Chooses whether `build()` keeps every input archive mapped up front or bounds
+    /// concurrently-open handles instead. Default: no-op, for builders with no such choice to
+    /// make. Implementors that do support it must be called before any `add_archive` call.
+    fn set_build_mode(&mut self, _mode: ArchiveBuildMode) {}
+
     fn build(self: Box<Self>, output: &Path) -> bool;
 }
 
@@ -88,22 +123,127 @@ pub trait ArchiveBuilder {
 pub struct ArArchiveBuilder<'a> {
     sess: &'a Session,
     object_reader: &'static ObjectReader,
-
-    src_archives: Vec<(PathBuf, Mmap)>,
+    metadata_mode: ArchiveMetadataMode,
+    build_mode: ArchiveBuildMode,
+
+    // In `AllAtOnce` mode each archive's mapping is made once in `add_archive` and cached
+    // here for reuse in `build_inner`. In `BoundedHandles` mode the mapping is always `None`;
+    // `build_inner` (re-)maps one archive at a time instead, so none has to stay resident for
+    // the builder's whole lifetime.
+    src_archives: Vec<(PathBuf, Option<Mmap>)>,
     // Don't use an `HashMap` here, as the order is important. `lib.rmeta` needs
     // to be at the end of an archive in some cases for linkers to not get confused.
     entries: Vec<(Vec<u8>, ArchiveEntry)>,
 }
 
+/// Selects how `build()` assembles the output archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveBuildMode {
+    /// Memory-map every input archive and file up front and hold them all resident while the
+    /// output is written. The default, and fine for link steps with only a handful of small
+    /// archives.
+    AllAtOnce,
+    /// Map (or copy) one member's bytes at a time, dropping each mapping before moving on to
+    /// the next member, so only one input source is mapped at any moment. This bounds
+    /// concurrently open file descriptors and mapped address space to one source at a time, at
+    /// the cost of redundant re-opening of archives with multiple members pulled into the
+    /// output. It does *not* bound peak heap memory: every member's bytes still end up copied
+    /// into an owned buffer and all of those buffers are held until the archive is written, so
+    /// total memory use is unchanged from `AllAtOnce`. Prefer this over `AllAtOnce` only when
+    /// address-space or descriptor limits, not memory, are what's binding.
+    BoundedHandles,
+}
+
+impl Default for ArchiveBuildMode {
+    fn default() -> Self {
+        ArchiveBuildMode::AllAtOnce
+    }
+}
+
+/// Controls whether per-member `mtime`/`uid`/`gid`/`perms` are carried through from the
+/// original file or input-archive member, or normalized to `0`/`0o644` for reproducible builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveMetadataMode {
+    /// Normalize every member's `mtime`/`uid`/`gid` to `0` and `perms` to `0o644`, the default,
+    /// so that archives built from the
+    /// same inputs are byte-for-byte identical regardless of the host's clock or file ownership.
+    Normalized,
+    /// Preserve each member's real `mtime`/`uid`/`gid`/`perms`.
+    Preserved,
+}
+
+impl Default for ArchiveMetadataMode {
+    fn default() -> Self {
+        ArchiveMetadataMode::Normalized
+    }
+}
+
+/// The subset of an archive member's header that `ar_archive_writer::NewArchiveMember` cares
+/// about. Defaults to the all-zero, `0o644` values `build_inner` has always hardcoded.
+#[derive(Debug, Clone, Copy)]
+struct EntryMetadata {
+    mtime: u64,
+    uid: u32,
+    gid: u32,
+    perms: u32,
+}
+
+impl Default for EntryMetadata {
+    fn default() -> Self {
+        EntryMetadata { mtime: 0, uid: 0, gid: 0, perms: 0o644 }
+    }
+}
+
+impl EntryMetadata {
+    /// `stat`s `path` so that, in `ArchiveMetadataMode::Preserved` mode, the member written to
+    /// the output archive carries the source file's real timestamp/ownership/permission bits.
+    #[cfg(unix)]
+    fn from_file(path: &Path) -> io::Result<EntryMetadata> {
+        use std::os::unix::fs::MetadataExt;
+
+        let meta = fs::metadata(path)?;
+        Ok(EntryMetadata {
+            mtime: meta.mtime() as u64,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            perms: meta.mode() & 0o777,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn from_file(_path: &Path) -> io::Result<EntryMetadata> {
+        Ok(EntryMetadata::default())
+    }
+
+    /// Copies the header fields of an `object::read::archive` member so that, in
+    /// `ArchiveMetadataMode::Preserved` mode, re-archived members round-trip their metadata
+    /// instead of being silently zeroed.
+    fn from_archive_member(entry: &object::read::archive::ArchiveMember<'_>) -> EntryMetadata {
+        EntryMetadata {
+            mtime: entry.date(),
+            uid: entry.uid(),
+            gid: entry.gid(),
+            perms: entry.mode() & 0o777,
+        }
+    }
+}
+
 #[derive(Debug)]
 enum ArchiveEntry {
-    FromArchive { archive_index: usize, file_range: (u64, u64) },
-    File(PathBuf),
+    FromArchive { archive_index: usize, file_range: (u64, u64), metadata: EntryMetadata },
+    File { path: PathBuf, metadata: EntryMetadata },
 }
 
 impl<'a> ArArchiveBuilder<'a> {
     pub fn new(sess: &'a Session, object_reader: &'static ObjectReader) -> ArArchiveBuilder<'a> {
-        ArArchiveBuilder { sess, object_reader, src_archives: vec![], entries: vec![] }
+        ArArchiveBuilder {
+            sess,
+            object_reader,
+            metadata_mode: ArchiveMetadataMode::Normalized,
+            build_mode: ArchiveBuildMode::AllAtOnce,
+            src_archives: vec![],
+            entries: vec![],
+        }
     }
 }
 
@@ -155,6 +295,27 @@ pub fn try_extract_macho_fat_archive(
 }
 
 impl<'a> ArchiveBuilder for ArArchiveBuilder<'a> {
+    /// Chooses whether members preserve their real metadata or are normalized to zero.
+    /// Archives built for reproducible builds should keep the default `Normalized` mode.
+    fn set_metadata_mode(&mut self, mode: ArchiveMetadataMode) {
+        self.metadata_mode = mode;
+    }
+
+    /// Chooses whether `build()` keeps every input archive mapped for the builder's whole
+    /// lifetime or maps one at a time on demand. Must be called, if at all, before any
+    /// `add_archive` call: `add_archive` consults the current mode to decide whether to cache
+    /// its mapping, so changing modes afterwards would leave earlier-added archives cached (or
+    /// not) according to whichever mode was set when they were added. Enforced by panicking
+    /// rather than silently allowed, since the earlier-cached state can't be fixed up
+    /// retroactively.
+    fn set_build_mode(&mut self, mode: ArchiveBuildMode) {
+        assert!(
+            self.src_archives.is_empty(),
+            "set_build_mode must be called before any add_archive call"
+        );
+        self.build_mode = mode;
+    }
+
     fn add_archive(
         &mut self,
         archive_path: &Path,
@@ -168,7 +329,7 @@ impl<'a> ArchiveBuilder for ArArchiveBuilder<'a> {
             }
         }
 
-        if self.src_archives.iter().any(|archive| archive.0 == archive_path) {
+        if self.src_archives.iter().any(|(path, _)| *path == archive_path) {
             return Ok(());
         }
 
@@ -184,20 +345,32 @@ impl<'a> ArchiveBuilder for ArArchiveBuilder<'a> {
             if !skip(&file_name) {
                 self.entries.push((
                     file_name.into_bytes(),
-                    ArchiveEntry::FromArchive { archive_index, file_range: entry.file_range() },
+                    ArchiveEntry::FromArchive {
+                        archive_index,
+                        file_range: entry.file_range(),
+                        metadata: EntryMetadata::from_archive_member(&entry),
+                    },
                 ));
             }
         }
 
-        self.src_archives.push((archive_path, archive_map));
+        // In `AllAtOnce` mode, keep this mapping around for `build_inner` to reuse instead of
+        // mapping the same archive a second time. In `BoundedHandles` mode, drop it now; the
+        // archive will be (re-)mapped on demand, one at a time, while writing the output.
+        let cached_map = match self.build_mode {
+            ArchiveBuildMode::AllAtOnce => Some(archive_map),
+            ArchiveBuildMode::BoundedHandles => None,
+        };
+        self.src_archives.push((archive_path, cached_map));
         Ok(())
     }
 
     /// Adds an arbitrary file to this archive
     fn add_file(&mut self, file: &Path) {
+        let metadata = EntryMetadata::from_file(file).unwrap_or_default();
         self.entries.push((
             file.file_name().unwrap().to_str().unwrap().to_string().into_bytes(),
-            ArchiveEntry::File(file.to_owned()),
+            ArchiveEntry::File { path: file.to_owned(), metadata },
         ));
     }
 
@@ -227,39 +400,25 @@ impl<'a> ArArchiveBuilder<'a> {
             }
         };
 
-        let mut entries = Vec::new();
-
-        for (entry_name, entry) in self.entries {
-            let data =
-                match entry {
-                    ArchiveEntry::FromArchive { archive_index, file_range } => {
-                        let src_archive = &self.src_archives[archive_index];
-
-                        let data = &src_archive.1
-                            [file_range.0 as usize..file_range.0 as usize + file_range.1 as usize];
-
-                        Box::new(data) as Box<dyn AsRef<[u8]>>
-                    }
-                    ArchiveEntry::File(file) => unsafe {
-                        Box::new(
-                            Mmap::map(File::open(file).map_err(|err| {
-                                io_error_context("failed to open object file", err)
-                            })?)
-                            .map_err(|err| io_error_context("failed to map object file", err))?,
-                        ) as Box<dyn AsRef<[u8]>>
-                    },
-                };
-
-            entries.push(NewArchiveMember {
-                buf: data,
-                object_reader: self.object_reader,
-                member_name: String::from_utf8(entry_name).unwrap(),
-                mtime: 0,
-                uid: 0,
-                gid: 0,
-                perms: 0o644,
-            })
-        }
+        let metadata_mode = self.metadata_mode;
+        let object_reader = self.object_reader;
+        let src_archives = self.src_archives;
+
+        let entries = match self.build_mode {
+            // Every entry in `src_archives` already carries its mapping, cached by `add_archive`.
+            ArchiveBuildMode::AllAtOnce => Self::collect_entries_all_at_once(
+                self.entries,
+                &src_archives,
+                object_reader,
+                metadata_mode,
+            )?,
+            ArchiveBuildMode::BoundedHandles => Self::collect_entries_bounded_handles(
+                self.entries,
+                &src_archives,
+                object_reader,
+                metadata_mode,
+            )?,
+        };
 
         // Write to a temporary file first before atomically renaming to the final name.
         // This prevents programs (including rustc) from attempting to read a partial archive.
@@ -288,10 +447,11 @@ impl<'a> ArArchiveBuilder<'a> {
         )?;
 
         let any_entries = !entries.is_empty();
+        // Drop all remaining mappings before renaming, which is necessary if we want to write
+        // the output archive to the same location as an input archive on Windows. `entries`
+        // must go first since, in `AllAtOnce` mode, it can borrow out of `src_archives`.
         drop(entries);
-        // Drop src_archives to unmap all input archives, which is necessary if we want to write the
-        // output archive to the same location as an input archive on Windows.
-        drop(self.src_archives);
+        drop(src_archives);
 
         fs::rename(archive_tmpfile_path, output)
             .map_err(|err| io_error_context("failed to rename archive file", err))?;
@@ -301,6 +461,122 @@ impl<'a> ArArchiveBuilder<'a> {
 
         Ok(any_entries)
     }
+
+    /// Slices each archive member's bytes out of its already-mapped source (every input archive
+    /// was mapped once by `add_archive` and that mapping cached in `src_archives`, so none of
+    /// them need to be (re-)opened here) and maps each standalone file added by `add_file`.
+    /// Simple, and the cheapest option when the total size of the inputs is small.
+    fn collect_entries_all_at_once<'m>(
+        entries: Vec<(Vec<u8>, ArchiveEntry)>,
+        src_archives: &'m [(PathBuf, Option<Mmap>)],
+        object_reader: &'static ObjectReader,
+        metadata_mode: ArchiveMetadataMode,
+    ) -> io::Result<Vec<NewArchiveMember<'m>>> {
+        let mut out = Vec::with_capacity(entries.len());
+        for (entry_name, entry) in entries {
+            let (data, metadata) = match entry {
+                ArchiveEntry::FromArchive { archive_index, file_range, metadata } => {
+                    let src_archive = src_archives[archive_index]
+                        .1
+                        .as_ref()
+                        .expect("add_archive must cache a mapping in AllAtOnce mode");
+                    let data = &src_archive
+                        [file_range.0 as usize..file_range.0 as usize + file_range.1 as usize];
+                    (Box::new(data) as Box<dyn AsRef<[u8]>>, metadata)
+                }
+                ArchiveEntry::File { path, metadata } => unsafe {
+                    (
+                        Box::new(
+                            Mmap::map(File::open(&path).map_err(|err| {
+                                io_error_context("failed to open object file", err)
+                            })?)
+                            .map_err(|err| io_error_context("failed to map object file", err))?,
+                        ) as Box<dyn AsRef<[u8]>>,
+                        metadata,
+                    )
+                },
+            };
+            out.push(Self::new_archive_member(
+                entry_name,
+                data,
+                metadata,
+                object_reader,
+                metadata_mode,
+            ));
+        }
+        Ok(out)
+    }
+
+    /// Processes `entries` in order, mapping (or reading) only the single member currently
+    /// being collected and dropping that mapping before moving on to the next one. Never holds
+    /// more than one input archive mapped at a time, trading some redundant re-opening of the
+    /// same archive for bounded address-space and file-descriptor usage. Each member's bytes are
+    /// still copied into an owned buffer and all of those buffers are held until the archive is
+    /// written, so this does *not* bound peak heap memory; see `ArchiveBuildMode::BoundedHandles`.
+    fn collect_entries_bounded_handles(
+        entries: Vec<(Vec<u8>, ArchiveEntry)>,
+        src_archives: &[(PathBuf, Option<Mmap>)],
+        object_reader: &'static ObjectReader,
+        metadata_mode: ArchiveMetadataMode,
+    ) -> io::Result<Vec<NewArchiveMember<'static>>> {
+        let mut out = Vec::with_capacity(entries.len());
+        for (entry_name, entry) in entries {
+            let (owned, metadata) = match entry {
+                ArchiveEntry::FromArchive { archive_index, file_range, metadata } => {
+                    let archive_path = &src_archives[archive_index].0;
+                    let archive_map = unsafe {
+                        Mmap::map(File::open(archive_path).map_err(|err| {
+                            io_error_context("failed to open input archive", err)
+                        })?)
+                        .map_err(|err| io_error_context("failed to map input archive", err))?
+                    };
+                    let data = &archive_map
+                        [file_range.0 as usize..file_range.0 as usize + file_range.1 as usize];
+                    let owned = data.to_vec();
+                    // `archive_map` is dropped here, before the next entry is touched.
+                    (owned, metadata)
+                }
+                ArchiveEntry::File { path, metadata } => {
+                    let mut file = File::open(&path)
+                        .map_err(|err| io_error_context("failed to open object file", err))?;
+                    let mut owned = Vec::new();
+                    io::copy(&mut file, &mut owned)
+                        .map_err(|err| io_error_context("failed to read object file", err))?;
+                    (owned, metadata)
+                }
+            };
+            out.push(Self::new_archive_member(
+                entry_name,
+                Box::new(owned) as Box<dyn AsRef<[u8]>>,
+                metadata,
+                object_reader,
+                metadata_mode,
+            ));
+        }
+        Ok(out)
+    }
+
+    fn new_archive_member<'m>(
+        entry_name: Vec<u8>,
+        data: Box<dyn AsRef<[u8]> + 'm>,
+        metadata: EntryMetadata,
+        object_reader: &'static ObjectReader,
+        metadata_mode: ArchiveMetadataMode,
+    ) -> NewArchiveMember<'m> {
+        let metadata = match metadata_mode {
+            ArchiveMetadataMode::Preserved => metadata,
+            ArchiveMetadataMode::Normalized => EntryMetadata::default(),
+        };
+        NewArchiveMember {
+            buf: data,
+            object_reader,
+            member_name: String::from_utf8(entry_name).unwrap(),
+            mtime: metadata.mtime,
+            uid: metadata.uid,
+            gid: metadata.gid,
+            perms: metadata.perms,
+        }
+    }
 }
 
 fn io_error_context(context: &str, err: io::Error) -> io::Error {